@@ -1,3 +1,17 @@
+mod coalescing_fetch;
+mod migration;
+mod peer_health;
+mod query_worker;
+mod streaming;
+mod subscription;
+
+pub use coalescing_fetch::{FetchCoalescer, FetchKey};
+pub use migration::MigratingDataSource;
+pub use peer_health::{HealthTrackingProvider, PeerHealth};
+pub use query_worker::{DecidedView, QueryWorker, QueryWorkerHandle};
+pub use streaming::{ResumeToken, StreamableDataSource};
+pub use subscription::{DecidedEvent, Notifier, SubscriptionDataSource};
+
 use super::{
     fs,
     options::{Options, Query},
@@ -84,6 +98,31 @@ pub fn provider<Ver: StaticVersionType + 'static>(
     provider
 }
 
+/// Create a provider alongside a [`HealthTrackingProvider`] tracking the same peers.
+///
+/// The returned `Provider` is unchanged from [`provider`] and is what the generic fetch/backfill
+/// machinery uses; `HealthTrackingProvider` is for callers (e.g. the background fetch worker) that
+/// want to drive their own per-peer request through [`HealthTrackingProvider::fetch`], trying
+/// peers in priority order and falling back on failure, with the resulting health surfaced to
+/// operators through [`PeerHealthDataSource`].
+pub fn provider_with_health<Ver: StaticVersionType + 'static>(
+    peers: impl IntoIterator<Item = Url>,
+    bind_version: Ver,
+) -> (Provider, HealthTrackingProvider) {
+    let peers: Vec<_> = peers.into_iter().collect();
+    let provider = provider(peers.clone(), bind_version);
+    let health = HealthTrackingProvider::new(peers);
+    (provider, health)
+}
+
+/// Visibility into the health of configured peers, so operators can see which peers are actually
+/// serving data. This is a trait of its own, not part of [`StatusDataSource`]; it is implemented
+/// by [`HealthTrackingProvider`], not by the data sources.
+#[async_trait]
+pub(crate) trait PeerHealthDataSource {
+    async fn peer_health(&self) -> Vec<PeerHealth>;
+}
+
 pub(crate) trait SubmitDataSource<N: network::Type> {
     fn consensus(&self) -> &SystemContextHandle<SeqTypes, Node<N>>;
 }
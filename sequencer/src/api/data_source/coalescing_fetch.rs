@@ -0,0 +1,291 @@
+//! Coalescing background fetches for missing objects.
+//!
+//! Without coalescing, N concurrent requests for the same missing height each trigger their own
+//! round trip to the [`Provider`](super::Provider), which is wasteful and, under load, can itself
+//! overwhelm peers. [`FetchCoalescer`] keeps track of fetches currently in flight; the first
+//! request for a given [`FetchKey`] spawns the real fetch and every other request for that same
+//! key just awaits the first one's result.
+
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::sync::Arc;
+use dashmap::DashMap;
+use futures::future::{FutureExt, Shared};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Identifies a single fetchable object: a block height or leaf height for now, but broad enough
+/// to cover merkle paths and VID common data too as those gain coalescing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FetchKey {
+    Leaf(u64),
+    Block(u64),
+    MerklePath { state: &'static str, height: u64 },
+}
+
+type FetchResult = Result<(), Arc<anyhow::Error>>;
+type FetchFuture = Shared<Pin<Box<dyn Future<Output = FetchResult> + Send>>>;
+
+/// An in-flight fetch, tagged with an id unique to this particular insertion.
+///
+/// The id lets a waiter that just finished awaiting `fut` tell whether the entry still in
+/// `in_flight` for its key is the same fetch it awaited, or whether (under churn: this fetch
+/// completed, a new one for the same key was already inserted, and another waiter is only now
+/// getting around to cleaning up) it's a newer fetch that must not be torn down early.
+struct InFlight {
+    id: u64,
+    fut: FetchFuture,
+}
+
+/// Coalesces concurrent fetches for the same [`FetchKey`] and bounds how many fetches are
+/// in flight at once.
+///
+/// Not tied to a persistence backend: it only runs the fetch closures it is given. Neither
+/// `fs::DataSource` nor `sql::DataSource` wraps one, so their own fetches of missing data are
+/// not coalesced or bounded by it.
+pub struct FetchCoalescer {
+    in_flight: DashMap<FetchKey, InFlight>,
+    next_id: AtomicU64,
+    // Bounds the number of fetch tasks actually running at once: the channel starts pre-loaded
+    // with `max_in_flight` tokens, a fetch acquires one before doing real work and returns it
+    // when done, so a burst of distinct missing keys applies backpressure instead of spawning
+    // unboundedly.
+    permits: Permits,
+}
+
+/// A simple counting semaphore built on a bounded channel pre-loaded with tokens.
+#[derive(Clone)]
+struct Permits {
+    tx: Sender<()>,
+    rx: Receiver<()>,
+}
+
+impl Permits {
+    fn new(count: usize) -> Self {
+        let (tx, rx) = bounded(count.max(1));
+        for _ in 0..count {
+            tx.try_send(()).expect("channel sized for `count` tokens");
+        }
+        Self { tx, rx }
+    }
+
+    /// Acquire a token, returning a guard that releases it back when dropped.
+    async fn acquire(&self) -> PermitGuard {
+        self.rx.recv().await.expect("sender kept alive by `self`");
+        PermitGuard { tx: self.tx.clone() }
+    }
+}
+
+struct PermitGuard {
+    tx: Sender<()>,
+}
+
+impl Drop for PermitGuard {
+    fn drop(&mut self) {
+        let _ = self.tx.try_send(());
+    }
+}
+
+impl FetchCoalescer {
+    /// Create a coalescer that allows at most `max_in_flight` fetches to run concurrently.
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            in_flight: DashMap::new(),
+            next_id: AtomicU64::new(0),
+            permits: Permits::new(max_in_flight),
+        }
+    }
+
+    /// Fetch `key`, coalescing with any fetch for the same key already in flight.
+    ///
+    /// `fetch` is only invoked for the caller that wins the race to insert the in-flight entry;
+    /// everyone else awaits that caller's future. The entry is removed once the fetch completes
+    /// (successfully or not) so a later, genuinely new fetch for the same key isn't coalesced with
+    /// a stale result; the removal is conditional on the entry still being the same one this
+    /// caller awaited (by id, not just by key), so a waiter that was slow to wake up after its
+    /// fetch completed can't delete a newer in-flight entry that raced in for the same key.
+    pub async fn fetch<F, Fut>(&self, key: FetchKey, fetch: F) -> anyhow::Result<()>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let entry = self.in_flight.entry(key.clone()).or_insert_with(|| {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let permits = self.permits.clone();
+            let key = key.clone();
+            let task: Pin<Box<dyn Future<Output = FetchResult> + Send>> = Box::pin(async move {
+                // Acquire a slot before doing any real work, so a burst of distinct missing
+                // keys doesn't spawn unbounded concurrent peer requests.
+                let _permit = permits.acquire().await;
+                let res = fetch().await.map_err(Arc::new);
+                tracing::trace!(?key, ok = res.is_ok(), "background fetch completed");
+                res
+            });
+            InFlight { id, fut: task.shared() }
+        });
+        let id = entry.id;
+        let fut = entry.fut.clone();
+        drop(entry);
+
+        let result = fut.await;
+        self.release(&key, id);
+        result.map_err(|err| anyhow::anyhow!("{err}"))
+    }
+
+    /// Remove `key`'s in-flight entry, but only if it's still the one tagged `id` — i.e. only if
+    /// nothing raced in a newer fetch for the same key while this caller was awaiting the old
+    /// one.
+    fn release(&self, key: &FetchKey, id: u64) {
+        self.in_flight.remove_if(key, |_, entry| entry.id == id);
+    }
+}
+
+impl Default for FetchCoalescer {
+    fn default() -> Self {
+        // A conservative default; callers wiring this into `fs`/`sql` options should size this
+        // from configuration instead of relying on the default in production.
+        Self::new(32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::{
+        sync::Mutex,
+        task::{sleep, yield_now},
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn concurrent_fetches_for_the_same_key_coalesce() {
+        let coalescer = Arc::new(FetchCoalescer::new(8));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let first = {
+            let coalescer = coalescer.clone();
+            let calls = calls.clone();
+            async_std::task::spawn(async move {
+                coalescer
+                    .fetch(FetchKey::Leaf(1), move || {
+                        let calls = calls.clone();
+                        async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            // Hold the slot open long enough for the second caller to arrive and
+                            // join this fetch instead of starting its own.
+                            sleep(Duration::from_millis(50)).await;
+                            Ok(())
+                        }
+                    })
+                    .await
+            })
+        };
+        // Give the first task a chance to insert its in-flight entry before the second call.
+        yield_now().await;
+
+        let second = {
+            let coalescer = coalescer.clone();
+            let calls = calls.clone();
+            async_std::task::spawn(async move {
+                coalescer
+                    .fetch(FetchKey::Leaf(1), move || {
+                        let calls = calls.clone();
+                        async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            Ok(())
+                        }
+                    })
+                    .await
+            })
+        };
+
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "fetch should only run once");
+    }
+
+    #[async_std::test]
+    async fn distinct_keys_do_not_coalesce() {
+        let coalescer = FetchCoalescer::new(8);
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        for key in [FetchKey::Leaf(1), FetchKey::Leaf(2)] {
+            let calls = calls.clone();
+            coalescer
+                .fetch(key, move || {
+                    let calls = calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn permits_bound_concurrent_fetches() {
+        let coalescer = Arc::new(FetchCoalescer::new(1));
+        let concurrent = Arc::new(Mutex::new(0usize));
+        let max_observed = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for height in 0..4u64 {
+            let coalescer = coalescer.clone();
+            let concurrent = concurrent.clone();
+            let max_observed = max_observed.clone();
+            tasks.push(async_std::task::spawn(async move {
+                coalescer
+                    .fetch(FetchKey::Leaf(height), move || {
+                        let concurrent = concurrent.clone();
+                        let max_observed = max_observed.clone();
+                        async move {
+                            let mut guard = concurrent.lock().await;
+                            *guard += 1;
+                            max_observed.fetch_max(*guard, Ordering::SeqCst);
+                            drop(guard);
+                            sleep(Duration::from_millis(10)).await;
+                            *concurrent.lock().await -= 1;
+                            Ok(())
+                        }
+                    })
+                    .await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(max_observed.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn a_stale_release_does_not_evict_a_newer_in_flight_fetch() {
+        // Reproduces the race `release`'s id check guards against: a waiter on an old fetch for
+        // `key` only gets around to cleaning up *after* a newer fetch for the same key has
+        // already raced in and replaced the entry. A release tagged with the old id must leave
+        // the newer entry alone.
+        let coalescer = FetchCoalescer::new(8);
+        let key = FetchKey::Leaf(1);
+
+        let pending: Pin<Box<dyn Future<Output = FetchResult> + Send>> = Box::pin(async { Ok(()) });
+        coalescer.in_flight.insert(
+            key.clone(),
+            InFlight {
+                id: 42,
+                fut: pending.shared(),
+            },
+        );
+
+        coalescer.release(&key, 7);
+        assert!(
+            coalescer.in_flight.get(&key).is_some(),
+            "a release with a stale id must not evict a newer entry"
+        );
+
+        coalescer.release(&key, 42);
+        assert!(coalescer.in_flight.get(&key).is_none());
+    }
+}
@@ -0,0 +1,249 @@
+//! Online migration between persistence backends.
+//!
+//! [`MigratingDataSource`] lets an operator move from one [`SequencerDataSource`] (e.g.
+//! `persistence::fs`) to another (e.g. `persistence::sql`) without stopping the sequencer to
+//! re-sync the new backend from scratch. Reads are served from `current`, falling back to
+//! `previous` for anything `current` hasn't backfilled yet; any object recovered from `previous`
+//! is immediately written into `current` so the fallback is only ever paid once per object.
+
+use super::SequencerDataSource;
+use async_std::sync::{Arc, RwLock};
+use async_trait::async_trait;
+use hotshot_query_service::{
+    availability::{
+        AvailabilityDataSource, BlockQueryData, LeafQueryData, QueryError, QueryResult,
+    },
+    data_source::{UpdateDataSource, VersionedDataSource},
+    merklized_state::MerklizedState,
+    node::NodeDataSource,
+    status::StatusDataSource,
+};
+use jf_primitives::merkle_tree::prelude::MerklePath;
+
+use crate::SeqTypes;
+
+/// A data source that migrates from `Previous` to `Current` while serving live traffic.
+///
+/// Reads consult `current` first; a miss (as opposed to an error) falls back to `previous`, and
+/// anything recovered that way is written back into `current` so it doesn't need to be fetched
+/// from `previous` again. Writes always go straight to `current`: once a backend is the `current`
+/// one it is expected to be the source of truth for new data going forward.
+pub struct MigratingDataSource<Previous, Current> {
+    previous: Previous,
+    // Needs interior mutability: `AvailabilityDataSource::get_leaf`/`get_block` take `&self`, but
+    // a fallback read that recovers an object from `previous` must still be able to write it into
+    // `current` so the fallback is only ever paid once per object.
+    current: RwLock<Current>,
+    /// Height up to which `migrate` has backfilled `current`, for resuming an interrupted run.
+    migrated_through: Arc<RwLock<u64>>,
+}
+
+impl<Previous, Current> MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource,
+    Current: SequencerDataSource,
+{
+    pub fn new(previous: Previous, current: Current) -> Self {
+        Self {
+            previous,
+            current: RwLock::new(current),
+            migrated_through: Arc::new(RwLock::new(0)),
+        }
+    }
+
+    /// Fetch `height` from `current`, falling back to `previous` and persisting the result into
+    /// `current` on a clean not-found.
+    async fn get_leaf_with_fallback(&self, height: u64) -> QueryResult<LeafQueryData<SeqTypes>> {
+        // Bind the read before matching on it: matching directly on
+        // `self.current.read().await.get_leaf(...).await` would keep the read guard alive (as a
+        // match-scrutinee temporary) for the whole `match`, deadlocking against the write lock
+        // taken below on a miss.
+        let read = self.current.read().await.get_leaf(height).await;
+        match read {
+            Ok(leaf) => Ok(leaf),
+            Err(QueryError::NotFound) => {
+                let leaf = self.previous.get_leaf(height).await?;
+                let mut current = self.current.write().await;
+                current.insert_leaf(leaf.clone()).await?;
+                current.commit().await?;
+                Ok(leaf)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get_block_with_fallback(&self, height: u64) -> QueryResult<BlockQueryData<SeqTypes>> {
+        // See the comment in `get_leaf_with_fallback`: the read guard must be dropped before the
+        // `NotFound` arm takes the write lock.
+        let read = self.current.read().await.get_block(height).await;
+        match read {
+            Ok(block) => Ok(block),
+            Err(QueryError::NotFound) => {
+                let block = self.previous.get_block(height).await?;
+                let mut current = self.current.write().await;
+                current.insert_block(block.clone()).await?;
+                current.commit().await?;
+                Ok(block)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Walk `range`, copying any object found only in `previous` into `current`.
+    ///
+    /// This is the bulk counterpart to the lazy, read-triggered backfill: an operator runs it
+    /// once after cutting over so that `current` converges even for heights nobody happens to
+    /// read. It takes `&mut self`, so no read through this data source runs while a call is in
+    /// progress; between calls, reads are served from whichever backend has the data. Since the
+    /// read path above already persists anything it
+    /// recovers, this just needs to trigger a read per height; it doesn't need to duplicate the
+    /// write.
+    pub async fn migrate(&mut self, range: std::ops::Range<u64>) -> anyhow::Result<()> {
+        for height in range {
+            self.get_leaf_with_fallback(height).await?;
+            self.get_block_with_fallback(height).await?;
+            *self.migrated_through.write().await = height + 1;
+        }
+        Ok(())
+    }
+
+    /// Height up to which `migrate` has already backfilled `current`.
+    pub async fn migrated_through(&self) -> u64 {
+        *self.migrated_through.read().await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> AvailabilityDataSource<SeqTypes> for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync,
+    Current: SequencerDataSource + Sync,
+{
+    async fn get_leaf(&self, height: u64) -> QueryResult<LeafQueryData<SeqTypes>> {
+        self.get_leaf_with_fallback(height).await
+    }
+
+    async fn get_block(&self, height: u64) -> QueryResult<BlockQueryData<SeqTypes>> {
+        self.get_block_with_fallback(height).await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> UpdateDataSource<SeqTypes> for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync,
+    Current: SequencerDataSource + Sync + Send,
+{
+    async fn update(&mut self, leaf: LeafQueryData<SeqTypes>, block: BlockQueryData<SeqTypes>) -> anyhow::Result<()> {
+        // New data always lands in `current`; `previous` is frozen as of the cutover.
+        self.current.write().await.update(leaf, block).await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> VersionedDataSource for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync,
+    Current: SequencerDataSource + Sync + Send,
+{
+    type Error = Current::Error;
+
+    async fn commit(&mut self) -> Result<(), Self::Error> {
+        self.current.write().await.commit().await
+    }
+
+    async fn revert(&mut self) {
+        self.current.write().await.revert().await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> NodeDataSource<SeqTypes> for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync,
+    Current: SequencerDataSource + Sync,
+{
+    async fn block_height(&self) -> QueryResult<u64> {
+        // `current` is always at least as far along as `previous`, since new blocks are only
+        // ever written there.
+        self.current.read().await.block_height().await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> StatusDataSource for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync,
+    Current: SequencerDataSource + Sync,
+{
+    async fn block_height(&self) -> QueryResult<u64> {
+        StatusDataSource::block_height(&*self.current.read().await).await
+    }
+}
+
+#[async_trait]
+impl<Previous, Current> SequencerDataSource for MigratingDataSource<Previous, Current>
+where
+    Previous: SequencerDataSource + Sync + Send,
+    Current: SequencerDataSource + Sync + Send,
+{
+    type Options = Current::Options;
+
+    async fn create(
+        _opt: Self::Options,
+        _provider: super::Provider,
+        _reset: bool,
+    ) -> anyhow::Result<Self> {
+        anyhow::bail!(
+            "MigratingDataSource cannot be constructed from `Options` alone; build `previous` and \
+             `current` independently and combine them with `MigratingDataSource::new`"
+        )
+    }
+
+    /// Store a merkle path, always in `current`; `previous` is read-only once migration starts.
+    async fn store_state<S: MerklizedState<SeqTypes>>(
+        &mut self,
+        path: MerklePath<S::Entry, S::Key, S::T>,
+        traversal_path: Vec<usize>,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        self.current
+            .write()
+            .await
+            .store_state::<S>(path, traversal_path, block_number)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_std::sync::RwLock;
+    use std::time::Duration;
+
+    /// Regression test for the locking discipline in `get_leaf_with_fallback`/
+    /// `get_block_with_fallback`: the read of `current` must complete (and drop its guard) before
+    /// a miss is handled by taking `current`'s write lock, or the two deadlock against each other
+    /// on the very first call. Exercising `MigratingDataSource` itself here would need real
+    /// `SeqTypes`/`LeafQueryData` fixtures this snapshot can't construct, so this instead runs the
+    /// same read-then-write-on-miss shape directly against a plain `RwLock`, with a timeout
+    /// standing in for "this used to hang forever".
+    #[async_std::test]
+    async fn fallback_read_does_not_deadlock_on_write_back() {
+        let current: RwLock<Option<u64>> = RwLock::new(None);
+
+        let outcome = async_std::future::timeout(Duration::from_secs(1), async {
+            let read = current.read().await.clone();
+            match read {
+                Some(value) => value,
+                None => {
+                    let mut guard = current.write().await;
+                    *guard = Some(42);
+                    42
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(outcome.expect("fallback read deadlocked"), 42);
+    }
+}
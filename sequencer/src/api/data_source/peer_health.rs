@@ -0,0 +1,295 @@
+//! Health-aware, priority-ordered peer tracking.
+//!
+//! Plain [`AnyProvider`] fans a fetch out to an unordered set of peers, which wastes requests on
+//! peers that are down or simply slow. [`HealthTrackingProvider`] doesn't replace `AnyProvider` as
+//! the thing the generic fetch/backfill machinery is built around (that machinery is generic over
+//! the upstream `Provider` trait, which we don't want to have to re-implement by hand here);
+//! instead a caller that wants priority-ordered, health-aware fetching drives its own per-peer
+//! request through [`fetch`](HealthTrackingProvider::fetch), which consults
+//! [`priority_order`](HealthTrackingProvider::priority_order) to pick which peer to try next and
+//! records the outcome (success with latency, or failure) via
+//! [`record_success`](HealthTrackingProvider::record_success) /
+//! [`record_failure`](HealthTrackingProvider::record_failure) as it goes. It records recent
+//! success/failure and latency per peer, backs off exponentially on a peer that's erroring or
+//! returning not-found, and quarantines it after too many consecutive failures in a row.
+
+use async_std::sync::{Arc, RwLock};
+use async_trait::async_trait;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tide_disco::Url;
+
+use super::PeerHealthDataSource;
+
+/// Consecutive failures before a peer is quarantined instead of merely deprioritized.
+const QUARANTINE_THRESHOLD: u32 = 5;
+
+/// Base delay for exponential backoff; doubles per consecutive failure up to `MAX_BACKOFF`.
+const BASE_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Ceiling on backoff delay. It is first reached at 8 consecutive failures, by which point the
+/// peer is quarantined and `priority_order` no longer consults its backoff.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Rolling health statistics for a single peer, exposed read-only through
+/// [`PeerHealthDataSource`], a trait separate from `StatusDataSource`.
+#[derive(Clone, Debug)]
+pub struct PeerHealth {
+    pub url: Url,
+    pub consecutive_failures: u32,
+    pub last_success: Option<Instant>,
+    pub last_failure: Option<Instant>,
+    pub last_latency: Option<Duration>,
+}
+
+impl PeerHealth {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+            last_latency: None,
+        }
+    }
+
+    /// Whether this peer is due to be tried again, given its current backoff.
+    fn is_available(&self, now: Instant) -> bool {
+        match (self.consecutive_failures, self.last_failure) {
+            (0, _) | (_, None) => true,
+            (n, Some(last_failure)) => now.duration_since(last_failure) >= backoff_delay(n),
+        }
+    }
+
+    /// A peer is quarantined once it's failed too many times in a row. `priority_order` then keeps
+    /// it among the last-resort peers (in configured order, alongside backed-off peers) even after
+    /// its backoff has elapsed, so it's only tried when every peer ahead of it fails; only a
+    /// success clears the quarantine.
+    fn quarantined(&self) -> bool {
+        self.consecutive_failures >= QUARANTINE_THRESHOLD
+    }
+
+    fn record_success(&mut self, latency: Duration) {
+        self.consecutive_failures = 0;
+        self.last_success = Some(Instant::now());
+        self.last_latency = Some(latency);
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.last_failure = Some(Instant::now());
+    }
+}
+
+fn backoff_delay(consecutive_failures: u32) -> Duration {
+    let scale = 1u32.checked_shl(consecutive_failures.min(16)).unwrap_or(u32::MAX);
+    (BASE_BACKOFF * scale).min(MAX_BACKOFF)
+}
+
+struct Peer {
+    url: Url,
+    health: RwLock<PeerHealth>,
+}
+
+/// Priority-ordering and backoff policy for a fixed list of peers, used alongside (not instead
+/// of) an [`AnyProvider`](super::Provider) built from the same peer URLs.
+///
+/// Tracks state and decides ordering; [`fetch`](Self::fetch) is the actual integration point a
+/// caller drives a real per-peer request through, trying peers in priority order and recording
+/// the outcome of each attempt as it goes.
+#[derive(Clone)]
+pub struct HealthTrackingProvider {
+    // Order is priority: index 0 is tried first among available peers.
+    peers: Arc<Vec<Peer>>,
+}
+
+impl HealthTrackingProvider {
+    pub fn new(peers: impl IntoIterator<Item = Url>) -> Self {
+        let peers = peers
+            .into_iter()
+            .map(|url| {
+                tracing::info!("tracking health of peer {url}, priority based on configured order");
+                Peer {
+                    health: RwLock::new(PeerHealth::new(url.clone())),
+                    url,
+                }
+            })
+            .collect();
+        Self {
+            peers: Arc::new(peers),
+        }
+    }
+
+    fn peer(&self, url: &Url) -> Option<&Peer> {
+        self.peers.iter().find(|peer| &peer.url == url)
+    }
+
+    /// Peers in the order they should currently be tried: available peers in configured priority
+    /// order, then backed-off and quarantined peers (also in configured order) as a fallback of
+    /// last resort, so a peer in backoff is still tried if nothing better is available.
+    pub async fn priority_order(&self) -> Vec<Url> {
+        let now = Instant::now();
+        let mut available = Vec::new();
+        let mut last_resort = Vec::new();
+        for peer in self.peers.iter() {
+            let health = peer.health.read().await;
+            if !health.quarantined() && health.is_available(now) {
+                available.push(peer.url.clone());
+            } else {
+                last_resort.push(peer.url.clone());
+            }
+        }
+        available.extend(last_resort);
+        available
+    }
+
+    /// Record that a fetch from `url` succeeded, resetting its backoff.
+    pub async fn record_success(&self, url: &Url, latency: Duration) {
+        if let Some(peer) = self.peer(url) {
+            peer.health.write().await.record_success(latency);
+        }
+    }
+
+    /// Record that a fetch from `url` failed (including a clean not-found), advancing its
+    /// backoff.
+    pub async fn record_failure(&self, url: &Url) {
+        if let Some(peer) = self.peer(url) {
+            peer.health.write().await.record_failure();
+        }
+    }
+
+    /// Snapshot of per-peer health, also served to operators through [`PeerHealthDataSource`].
+    pub async fn peer_health(&self) -> Vec<PeerHealth> {
+        let mut out = Vec::with_capacity(self.peers.len());
+        for peer in self.peers.iter() {
+            out.push(peer.health.read().await.clone());
+        }
+        out
+    }
+
+    /// Try `fetch_peer` against each configured peer in priority order, stopping at the first
+    /// success and recording the outcome (success with latency, or failure) against that peer's
+    /// health so later calls prefer peers that are actually serving data.
+    pub async fn fetch<F, Fut, T>(&self, mut fetch_peer: F) -> Option<T>
+    where
+        F: FnMut(Url) -> Fut,
+        Fut: Future<Output = Option<T>>,
+    {
+        for url in self.priority_order().await {
+            let start = Instant::now();
+            if let Some(value) = fetch_peer(url.clone()).await {
+                self.record_success(&url, start.elapsed()).await;
+                return Some(value);
+            }
+            self.record_failure(&url).await;
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl PeerHealthDataSource for HealthTrackingProvider {
+    async fn peer_health(&self) -> Vec<PeerHealth> {
+        // Calls the inherent method above, not recursing: an inherent method always shadows a
+        // trait method of the same name in method-call syntax.
+        self.peer_health().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn url(n: u16) -> Url {
+        format!("http://peer-{n}.example:8080").parse().unwrap()
+    }
+
+    #[test]
+    fn backoff_delay_doubles_and_caps() {
+        assert_eq!(backoff_delay(0), BASE_BACKOFF);
+        assert_eq!(backoff_delay(1), BASE_BACKOFF * 2);
+        assert_eq!(backoff_delay(2), BASE_BACKOFF * 4);
+        assert_eq!(backoff_delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn quarantine_kicks_in_after_threshold() {
+        let mut health = PeerHealth::new(url(0));
+        for _ in 0..QUARANTINE_THRESHOLD - 1 {
+            health.record_failure();
+            assert!(!health.quarantined());
+        }
+        health.record_failure();
+        assert!(health.quarantined());
+
+        health.record_success(Duration::from_millis(10));
+        assert!(!health.quarantined());
+        assert_eq!(health.consecutive_failures, 0);
+    }
+
+    #[async_std::test]
+    async fn healthy_peers_are_tried_before_backed_off_ones() {
+        let provider = HealthTrackingProvider::new([url(0), url(1)]);
+        provider.record_failure(&url(0)).await;
+
+        // `url(0)` just failed once (backed off, not yet quarantined) and `url(1)` is untouched,
+        // so `url(1)` should be preferred, but `url(0)` must still appear as a last resort rather
+        // than being dropped entirely.
+        let order = provider.priority_order().await;
+        assert_eq!(order, vec![url(1), url(0)]);
+    }
+
+    #[async_std::test]
+    async fn quarantined_peer_is_still_a_last_resort() {
+        let provider = HealthTrackingProvider::new([url(0)]);
+        for _ in 0..QUARANTINE_THRESHOLD {
+            provider.record_failure(&url(0)).await;
+        }
+
+        // With no other peer configured, the only peer must still be returned (as a last resort)
+        // rather than leaving the caller with nothing to try.
+        assert_eq!(provider.priority_order().await, vec![url(0)]);
+    }
+
+    #[async_std::test]
+    async fn record_success_resets_backoff() {
+        let provider = HealthTrackingProvider::new([url(0), url(1)]);
+        provider.record_failure(&url(0)).await;
+        provider.record_success(&url(0), Duration::from_millis(5)).await;
+
+        assert_eq!(provider.priority_order().await, vec![url(0), url(1)]);
+    }
+
+    #[async_std::test]
+    async fn fetch_falls_through_to_the_next_peer_and_records_outcomes() {
+        let provider = HealthTrackingProvider::new([url(0), url(1)]);
+
+        // `url(0)` is first in priority order but always fails; `fetch` should fall through to
+        // `url(1)` and return its value, recording a failure against `url(0)` and a success
+        // against `url(1)` along the way.
+        let result = provider
+            .fetch(|peer| async move { if peer == url(0) { None } else { Some(peer) } })
+            .await;
+        assert_eq!(result, Some(url(1)));
+
+        let health = provider.peer_health().await;
+        let health_of = |u: &Url| health.iter().find(|h| &h.url == u).unwrap();
+        assert_eq!(health_of(&url(0)).consecutive_failures, 1);
+        assert_eq!(health_of(&url(1)).consecutive_failures, 0);
+        assert!(health_of(&url(1)).last_success.is_some());
+
+        // `url(0)`'s failure should also have demoted it below `url(1)` for the next attempt.
+        assert_eq!(provider.priority_order().await, vec![url(1), url(0)]);
+    }
+
+    #[async_std::test]
+    async fn fetch_returns_none_when_every_peer_fails() {
+        let provider = HealthTrackingProvider::new([url(0), url(1)]);
+
+        let result: Option<()> = provider.fetch(|_| async { None }).await;
+        assert_eq!(result, None);
+
+        let health = provider.peer_health().await;
+        assert!(health.iter().all(|h| h.consecutive_failures == 1));
+    }
+}
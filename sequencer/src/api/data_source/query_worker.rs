@@ -0,0 +1,122 @@
+//! Off-chain query worker.
+//!
+//! `store_state` and the rest of the [`UpdateDataSource`] writes used to be driven inline on the
+//! consensus path, so a slow query database (a big SQL write, merkle backfill) could back-pressure
+//! block decision itself. [`QueryWorker`] instead owns the availability + merklized-state database
+//! exclusively: consensus publishes [`DecidedView`]s to a bounded channel and returns immediately,
+//! and the worker drains that channel on its own task, independent of consensus's latency budget.
+
+use super::{DecidedEvent, Notifier, SequencerDataSource};
+use async_std::channel::{bounded, Receiver, Sender, TrySendError};
+use async_std::sync::{Arc, RwLock};
+use async_std::task::JoinHandle;
+use hotshot_query_service::{data_source::UpdateDataSource, merklized_state::MerklizedState};
+
+use crate::SeqTypes;
+
+/// A single decided view, handed off from consensus to the query worker.
+pub struct DecidedView {
+    pub leaf: hotshot_query_service::availability::LeafQueryData<SeqTypes>,
+    pub block: hotshot_query_service::availability::BlockQueryData<SeqTypes>,
+    pub state: Arc<crate::state::ValidatedState>,
+}
+
+/// How many decided views may be queued for the worker before `publish` starts shedding them.
+///
+/// This is deliberately generous: the whole point of the worker is that a temporarily slow query
+/// DB shouldn't stall consensus, so the channel needs enough slack to absorb a burst of decided
+/// views while the worker catches up before anything actually gets dropped.
+const QUERY_QUEUE_DEPTH: usize = 256;
+
+/// A handle consensus uses to hand decided views to the off-chain query worker.
+#[derive(Clone)]
+pub struct QueryWorkerHandle {
+    sender: Sender<DecidedView>,
+}
+
+impl QueryWorkerHandle {
+    /// Publish a newly decided view, queuing it for the worker without waiting for it to actually
+    /// be written to the query database.
+    ///
+    /// This must never block: if the worker has fallen far enough behind that the queue is full,
+    /// the whole point of decoupling the query DB from consensus is defeated if `publish` then
+    /// blocks the consensus task that calls it. So a full queue sheds this view (logging it) and
+    /// returns immediately instead of waiting for room.
+    pub fn publish(&self, view: DecidedView) -> anyhow::Result<()> {
+        match self.sender.try_send(view) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(view)) => {
+                tracing::warn!(
+                    height = view.leaf.height(),
+                    "query worker queue is full, dropping decided view; query DB will be behind \
+                     until the worker catches up"
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(anyhow::anyhow!("query worker has shut down")),
+        }
+    }
+}
+
+/// Owns the query-side database and applies decided views to it on a dedicated task, fully
+/// decoupled from the consensus loop that produces them.
+pub struct QueryWorker<D> {
+    receiver: Receiver<DecidedView>,
+    data_source: Arc<RwLock<D>>,
+    notifier: Notifier,
+}
+
+impl<D> QueryWorker<D>
+where
+    D: SequencerDataSource + Send + Sync + 'static,
+{
+    /// Spawn the worker, returning the handle consensus publishes decided views through and a
+    /// join handle for shutdown.
+    pub fn spawn(data_source: Arc<RwLock<D>>, notifier: Notifier) -> (QueryWorkerHandle, JoinHandle<()>) {
+        let (sender, receiver) = bounded(QUERY_QUEUE_DEPTH);
+        let worker = Self {
+            receiver,
+            data_source,
+            notifier,
+        };
+        let task = async_std::task::spawn(worker.run());
+        (QueryWorkerHandle { sender }, task)
+    }
+
+    async fn run(self) {
+        while let Ok(view) = self.receiver.recv().await {
+            if let Err(err) = self.apply(view).await {
+                // The query DB is no longer the source of truth for anything consensus depends
+                // on, so a write failure here is only logged, not propagated anywhere that could
+                // affect block decision. The failed view is not retried and is not announced to
+                // subscribers.
+                tracing::error!("query worker failed to apply decided view: {err:#}");
+            }
+        }
+        tracing::info!("query worker shutting down: channel closed");
+    }
+
+    async fn apply(&self, view: DecidedView) -> anyhow::Result<()> {
+        let DecidedView { leaf, block, state } = view;
+        let mut ds = self.data_source.write().await;
+        ds.update(leaf.clone(), block.clone()).await?;
+        ds.commit().await.map_err(|e| anyhow::anyhow!("{e}"))?;
+        drop(ds);
+
+        self.notifier.notify(DecidedEvent { leaf, block, state });
+        Ok(())
+    }
+
+    /// Store a merkle path for a previously decided block. This runs on the calling task, not on
+    /// the worker's: it takes the same write lock on the data source that the worker holds while
+    /// applying a view.
+    pub async fn store_state<S: MerklizedState<SeqTypes>>(
+        &self,
+        path: jf_primitives::merkle_tree::prelude::MerklePath<S::Entry, S::Key, S::T>,
+        traversal_path: Vec<usize>,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let mut ds = self.data_source.write().await;
+        ds.store_state::<S>(path, traversal_path, block_number).await
+    }
+}